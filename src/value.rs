@@ -7,6 +7,18 @@ use protobuf::stream::wire_format;
 use crate::descriptor;
 use crate::error;
 
+/// The default maximum nesting depth `Message::merge_from` will follow before
+/// giving up with `error::Error::RecursionLimitExceeded`. Matches the
+/// `DEFAULT_RECURSION_LIMIT` used by the C++ implementation.
+pub const DEFAULT_RECURSION_LIMIT: u32 = 100;
+
+/// The default cap on how large an allocation a single length-delimited read
+/// (a submessage, a packed scalar run, or a `bytes`/`string` value) may
+/// request based on a declared wire length, before any of that data has
+/// actually been read. Guards against a short, hostile input claiming an
+/// implausibly large payload.
+pub const DEFAULT_READ_RAW_BYTES_MAX_ALLOC: u64 = 10 * 1024 * 1024;
+
 /// Any protobuf value.
 #[derive(Clone, Debug)]
 pub enum Value {
@@ -43,6 +55,8 @@ pub struct Message {
     pub unknown: protobuf::UnknownFields,
 
     size: protobuf::CachedSize,
+    recursion_limit: u32,
+    max_alloc_bytes: u64,
 }
 
 /// A message field value.
@@ -62,6 +76,8 @@ impl Message {
             fields: collections::BTreeMap::new(),
             unknown: protobuf::UnknownFields::new(),
             size: Default::default(),
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            max_alloc_bytes: DEFAULT_READ_RAW_BYTES_MAX_ALLOC,
         };
 
         for field in message.fields() {
@@ -78,6 +94,23 @@ impl Message {
         m
     }
 
+    /// Sets the maximum depth of nested submessages that `merge_from` will
+    /// follow before returning `error::Error::RecursionLimitExceeded`.
+    /// Defaults to `DEFAULT_RECURSION_LIMIT`.
+    #[inline]
+    pub fn set_recursion_limit(&mut self, limit: u32) {
+        self.recursion_limit = limit;
+    }
+
+    /// Sets the largest allocation a single length-delimited read (a
+    /// submessage, a packed scalar run, or a `bytes`/`string` value) may make
+    /// on the strength of a declared wire length, before any of that length
+    /// has actually been read. Defaults to `DEFAULT_READ_RAW_BYTES_MAX_ALLOC`.
+    #[inline]
+    pub fn set_max_alloc_bytes(&mut self, max_alloc_bytes: u64) {
+        self.max_alloc_bytes = max_alloc_bytes;
+    }
+
     /// Merge data from the given input stream into this message.
     #[inline]
     pub fn merge_from(
@@ -85,13 +118,33 @@ impl Message {
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
         input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        let limit = self.recursion_limit;
+        let max_alloc = self.max_alloc_bytes;
+        self.merge_from_bounded(descriptors, message, input, limit, 0, max_alloc)
+    }
+
+    /// Like `merge_from`, but carries the configured recursion `limit`, the
+    /// current nesting `depth`, and the configured allocation cap
+    /// (`max_alloc`) down through submessages so a long chain of nested
+    /// messages can be rejected instead of overflowing the stack, and a
+    /// hostile length prefix rejected before it is acted on.
+    #[inline]
+    fn merge_from_bounded(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        limit: u32,
+        depth: u32,
+        max_alloc: u64,
     ) -> error::Result<()> {
         while !input.eof()? {
             let (number, wire_type) = input.read_tag_unpack()?;
 
             if let Some(field) = message.field_by_number(number as i32) {
                 let value = self.ensure_field(field);
-                value.merge_from(descriptors, field, input, wire_type)?;
+                value.merge_from(descriptors, field, input, wire_type, limit, depth, max_alloc)?;
             } else {
                 use protobuf::rt::read_unknown_or_skip_group as u;
                 u(number, wire_type, input, &mut self.unknown)?;
@@ -101,10 +154,17 @@ impl Message {
     }
 
     // Sum of all field sizes
-    fn compute_size(&self) -> u32 {
+    fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> u32 {
         let mut size = 0;
         for (tag, field) in &self.fields {
-            size += field.size_with_tag(*tag as u32);
+            let field_descriptor = message
+                .field_by_number(*tag)
+                .expect("Message field missing from its descriptor");
+            size += field.size_with_tag(*tag as u32, field_descriptor, descriptors);
         }
         size += protobuf::rt::unknown_fields_size(&self.unknown);
         self.size.set(size);
@@ -112,20 +172,32 @@ impl Message {
     }
 
     /// Write this message to the given output stream.
-    pub fn write_to(&self, os: &mut protobuf::CodedOutputStream) -> error::Result<()> {
-        self.compute_size();
+    pub fn write_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        os: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        self.compute_size(descriptors, message);
         for (tag, field) in &self.fields {
-            field.write_to_with_tag(*tag as u32, os, false)?;
+            let field_descriptor = message
+                .field_by_number(*tag)
+                .expect("Message field missing from its descriptor");
+            field.write_to_with_tag(*tag as u32, field_descriptor, descriptors, os, false)?;
         }
         os.write_unknown_fields(&self.unknown)?;
         Ok(())
     }
 
     /// Write this message to a byte vector.
-    pub fn write_to_bytes(&self) -> error::Result<Vec<u8>> {
+    pub fn write_to_bytes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<Vec<u8>> {
         let mut v = Vec::new();
         let mut stream = protobuf::CodedOutputStream::vec(&mut v);
-        self.write_to(&mut stream)?;
+        self.write_to(descriptors, message, &mut stream)?;
         stream.flush()?;
         Ok(v)
     }
@@ -136,6 +208,314 @@ impl Message {
             .entry(field.number())
             .or_insert_with(|| Field::new(field))
     }
+
+    fn field_i64(&self, number: i32) -> i64 {
+        match self.fields.get(&number) {
+            Some(Field::Singular(Some(Value::I64(x)))) => *x,
+            _ => 0,
+        }
+    }
+
+    fn field_i32(&self, number: i32) -> i32 {
+        match self.fields.get(&number) {
+            Some(Field::Singular(Some(Value::I32(x)))) => *x,
+            _ => 0,
+        }
+    }
+
+    fn field_string(&self, number: i32) -> &str {
+        match self.fields.get(&number) {
+            Some(Field::Singular(Some(Value::String(s)))) => s.as_str(),
+            _ => "",
+        }
+    }
+
+    fn field_bytes(&self, number: i32) -> &[u8] {
+        match self.fields.get(&number) {
+            Some(Field::Singular(Some(Value::Bytes(b)))) => b.as_slice(),
+            _ => &[],
+        }
+    }
+
+    fn set_field_i64(&mut self, number: i32, value: i64) {
+        self.fields
+            .insert(number, Field::Singular(Some(Value::I64(value))));
+    }
+
+    fn set_field_i32(&mut self, number: i32, value: i32) {
+        self.fields
+            .insert(number, Field::Singular(Some(Value::I32(value))));
+    }
+}
+
+/// Conversions between `Message` and the Protobuf well-known types
+/// (`google.protobuf.{Timestamp,Duration,Struct,Value,Any}`). The `as_*`
+/// accessors only convert a message if `message` (its own descriptor)
+/// actually names the corresponding well-known type, so they are safe to try
+/// speculatively on any message. The `from_*` builders do not check the
+/// descriptor's name.
+impl Message {
+    /// If `message` describes `google.protobuf.Timestamp`, returns its
+    /// `(seconds, nanos)` pair.
+    pub fn as_timestamp(&self, message: &descriptor::MessageDescriptor) -> Option<(i64, i32)> {
+        if message.full_name() != well_known::TIMESTAMP {
+            return None;
+        }
+        Some((self.field_i64(1), self.field_i32(2)))
+    }
+
+    /// Builds a `google.protobuf.Timestamp` message from a `(seconds, nanos)`
+    /// pair.
+    pub fn from_timestamp(
+        message: &descriptor::MessageDescriptor,
+        seconds: i64,
+        nanos: i32,
+    ) -> Message {
+        let mut m = Message::new(message);
+        m.set_field_i64(1, seconds);
+        m.set_field_i32(2, nanos);
+        m
+    }
+
+    /// If `message` describes `google.protobuf.Duration`, returns its
+    /// `(seconds, nanos)` pair.
+    pub fn as_duration(&self, message: &descriptor::MessageDescriptor) -> Option<(i64, i32)> {
+        if message.full_name() != well_known::DURATION {
+            return None;
+        }
+        Some((self.field_i64(1), self.field_i32(2)))
+    }
+
+    /// Builds a `google.protobuf.Duration` message from a `(seconds, nanos)`
+    /// pair.
+    pub fn from_duration(
+        message: &descriptor::MessageDescriptor,
+        seconds: i64,
+        nanos: i32,
+    ) -> Message {
+        let mut m = Message::new(message);
+        m.set_field_i64(1, seconds);
+        m.set_field_i32(2, nanos);
+        m
+    }
+
+    /// If `message` describes `google.protobuf.Value`, returns its decoded
+    /// oneof.
+    pub fn as_value(&self, message: &descriptor::MessageDescriptor) -> Option<WellKnownValue> {
+        if message.full_name() != well_known::VALUE {
+            return None;
+        }
+        Some(self.decode_value_oneof())
+    }
+
+    /// Builds a `google.protobuf.Value` message from a `WellKnownValue`.
+    pub fn from_value(message: &descriptor::MessageDescriptor, value: &WellKnownValue) -> Message {
+        let mut m = Message::new(message);
+        m.fields = Message::encode_value_oneof(value).fields;
+        m
+    }
+
+    /// Builds the raw fields map a `google.protobuf.Value` submessage would
+    /// have for `value`, without needing that submessage's own descriptor
+    /// (every field set on it is set explicitly, so `Message::new`'s
+    /// descriptor-driven defaulting isn't needed). Shared by `from_value`
+    /// and by the `struct_value`/`list_value` branches below, which nest
+    /// `Value` messages arbitrarily deep.
+    fn encode_value_oneof(value: &WellKnownValue) -> Message {
+        let mut fields = collections::BTreeMap::new();
+        match value {
+            WellKnownValue::Null => {
+                fields.insert(1, Field::Singular(Some(Value::Enum(0))));
+            }
+            WellKnownValue::Number(n) => {
+                fields.insert(2, Field::Singular(Some(Value::F64(*n))));
+            }
+            WellKnownValue::String(s) => {
+                fields.insert(3, Field::Singular(Some(Value::String(s.clone()))));
+            }
+            WellKnownValue::Bool(b) => {
+                fields.insert(4, Field::Singular(Some(Value::Bool(*b))));
+            }
+            WellKnownValue::Struct(s) => {
+                fields.insert(
+                    5,
+                    Field::Singular(Some(Value::Message(Message::encode_struct_fields(s)))),
+                );
+            }
+            WellKnownValue::List(values) => {
+                let elems = values
+                    .iter()
+                    .map(|v| Value::Message(Message::encode_value_oneof(v)))
+                    .collect();
+                fields.insert(
+                    6,
+                    Field::Singular(Some(Value::Message(Message::raw(
+                        collections::BTreeMap::from([(1, Field::Repeated(elems))]),
+                    )))),
+                );
+            }
+        }
+        Message::raw(fields)
+    }
+
+    /// Builds the raw fields map a `google.protobuf.Struct` submessage would
+    /// have for `fields`, as `repeated { string key = 1;
+    /// google.protobuf.Value value = 2; }` entries. See `encode_value_oneof`
+    /// for why no descriptor is needed.
+    fn encode_struct_fields(fields: &collections::BTreeMap<String, WellKnownValue>) -> Message {
+        let entries = fields
+            .iter()
+            .map(|(k, v)| {
+                Value::Message(Message::raw(collections::BTreeMap::from([
+                    (1, Field::Singular(Some(Value::String(k.clone())))),
+                    (
+                        2,
+                        Field::Singular(Some(Value::Message(Message::encode_value_oneof(v)))),
+                    ),
+                ])))
+            })
+            .collect();
+        Message::raw(collections::BTreeMap::from([(1, Field::Repeated(entries))]))
+    }
+
+    /// If `message` describes `google.protobuf.Struct`, flattens its
+    /// `fields` map into a `BTreeMap`, decoding each entry's
+    /// `google.protobuf.Value` oneof via `decode_value_oneof`.
+    pub fn as_struct(
+        &self,
+        message: &descriptor::MessageDescriptor,
+    ) -> Option<collections::BTreeMap<String, WellKnownValue>> {
+        if message.full_name() != well_known::STRUCT {
+            return None;
+        }
+        Some(self.decode_struct_fields())
+    }
+
+    /// Flattens a `google.protobuf.Struct`-shaped `fields` map (`repeated {
+    /// string key = 1; google.protobuf.Value value = 2; }`) into a
+    /// `BTreeMap`, decoding each entry's value oneof.
+    fn decode_struct_fields(&self) -> collections::BTreeMap<String, WellKnownValue> {
+        let mut fields = collections::BTreeMap::new();
+        if let Some(Field::Repeated(entries)) = self.fields.get(&1) {
+            for entry in entries {
+                if let Value::Message(entry) = entry {
+                    if let Some(Field::Singular(Some(Value::Message(v)))) = entry.fields.get(&2) {
+                        fields.insert(entry.field_string(1).to_owned(), v.decode_value_oneof());
+                    }
+                }
+            }
+        }
+        fields
+    }
+
+    /// Decodes this message as a `google.protobuf.Value`'s oneof, assuming
+    /// its shape (field numbers 1-6 for `null_value`/`number_value`/
+    /// `string_value`/`bool_value`/`struct_value`/`list_value`) without
+    /// checking its descriptor's name.
+    fn decode_value_oneof(&self) -> WellKnownValue {
+        if let Some(Field::Singular(Some(Value::F64(n)))) = self.fields.get(&2) {
+            return WellKnownValue::Number(*n);
+        }
+        if let Some(Field::Singular(Some(Value::String(s)))) = self.fields.get(&3) {
+            return WellKnownValue::String(s.clone());
+        }
+        if let Some(Field::Singular(Some(Value::Bool(b)))) = self.fields.get(&4) {
+            return WellKnownValue::Bool(*b);
+        }
+        if let Some(Field::Singular(Some(Value::Message(m)))) = self.fields.get(&5) {
+            return WellKnownValue::Struct(m.decode_struct_fields());
+        }
+        if let Some(Field::Singular(Some(Value::Message(m)))) = self.fields.get(&6) {
+            let mut values = Vec::new();
+            if let Some(Field::Repeated(entries)) = m.fields.get(&1) {
+                for entry in entries {
+                    if let Value::Message(entry) = entry {
+                        values.push(entry.decode_value_oneof());
+                    }
+                }
+            }
+            return WellKnownValue::List(values);
+        }
+        WellKnownValue::Null
+    }
+
+    /// Builds a `Message` directly from a field map, bypassing
+    /// `Message::new`'s descriptor-driven default population. Used to build
+    /// synthetic submessages (map entries, list elements) for well-known
+    /// types where every field is set explicitly.
+    fn raw(fields: collections::BTreeMap<i32, Field>) -> Message {
+        Message {
+            fields,
+            unknown: protobuf::UnknownFields::new(),
+            size: Default::default(),
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            max_alloc_bytes: DEFAULT_READ_RAW_BYTES_MAX_ALLOC,
+        }
+    }
+
+    /// If `message` describes `google.protobuf.Any`, returns its
+    /// `(type_url, value)` pair.
+    pub fn as_any<'a>(
+        &'a self,
+        message: &descriptor::MessageDescriptor,
+    ) -> Option<(&'a str, &'a [u8])> {
+        if message.full_name() != well_known::ANY {
+            return None;
+        }
+        Some((self.field_string(1), self.field_bytes(2)))
+    }
+
+    /// Unpacks a `google.protobuf.Any` by resolving its `type_url` against
+    /// `descriptors` and parsing `value` as that message type.
+    pub fn unpack(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        descriptors: &descriptor::Descriptors,
+    ) -> error::Result<Message> {
+        let (type_url, value) = self.as_any(message).ok_or_else(|| error::Error::UnknownMessage {
+            name: message.full_name().to_owned(),
+        })?;
+
+        let type_name = type_url.rsplit('/').next().unwrap_or(type_url);
+        let inner_descriptor =
+            descriptors
+                .message_by_name(type_name)
+                .ok_or_else(|| error::Error::UnknownMessage {
+                    name: type_name.to_owned(),
+                })?;
+
+        let mut inner = Message::new(inner_descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(value);
+        inner.merge_from(descriptors, inner_descriptor, &mut input)?;
+        Ok(inner)
+    }
+}
+
+/// Fully-qualified names of the well-known types `Message` knows how to
+/// convert to and from.
+mod well_known {
+    pub const TIMESTAMP: &str = "google.protobuf.Timestamp";
+    pub const DURATION: &str = "google.protobuf.Duration";
+    pub const STRUCT: &str = "google.protobuf.Struct";
+    pub const VALUE: &str = "google.protobuf.Value";
+    pub const ANY: &str = "google.protobuf.Any";
+}
+
+/// The decoded oneof held by a `google.protobuf.Value`.
+#[derive(Clone, Debug)]
+pub enum WellKnownValue {
+    /// `null_value`.
+    Null,
+    /// `number_value`.
+    Number(f64),
+    /// `string_value`.
+    String(String),
+    /// `bool_value`.
+    Bool(bool),
+    /// `struct_value`, flattened the same way as `Message::as_struct`.
+    Struct(collections::BTreeMap<String, WellKnownValue>),
+    /// `list_value`.
+    List(Vec<WellKnownValue>),
 }
 
 impl Field {
@@ -150,6 +530,12 @@ impl Field {
     }
 
     /// Merge data from the given input stream into this field.
+    ///
+    /// `limit` and `depth` are the configured recursion limit and the current
+    /// submessage nesting depth, forwarded to `merge_message`/`merge_group` so
+    /// they can guard against unbounded recursion. `max_alloc` is the cap on
+    /// how large an allocation a declared length prefix may request, checked
+    /// before any of that length is read.
     #[inline]
     pub fn merge_from(
         &mut self,
@@ -157,19 +543,15 @@ impl Field {
         field: &descriptor::FieldDescriptor,
         input: &mut protobuf::CodedInputStream,
         wire_type: protobuf::stream::wire_format::WireType,
+        limit: u32,
+        depth: u32,
+        max_alloc: u64,
     ) -> error::Result<()> {
         // Make the type dispatch below more compact
         use crate::descriptor::FieldType::*;
         use protobuf::stream::wire_format::WireType::*;
         use protobuf::CodedInputStream as I;
 
-        // Singular scalar
-        macro_rules! ss {
-            ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
-                self.merge_scalar(input, wire_type, $expected_wire_type, $visit_func, $reader)
-            };
-        }
-
         // Packable scalar
         macro_rules! ps {
             ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
@@ -179,6 +561,7 @@ impl Field {
                     $expected_wire_type,
                     $visit_func,
                     $reader,
+                    max_alloc,
                 )
             };
             ($expected_wire_type:expr, $size:expr, $visit_func:expr, $reader:expr) => {
@@ -189,6 +572,7 @@ impl Field {
                     $expected_wire_type,
                     $visit_func,
                     $reader,
+                    max_alloc,
                 )
             };
         }
@@ -207,55 +591,50 @@ impl Field {
             SFixed64 => ps!(WireTypeFixed64, 8, Value::I64, I::read_sfixed64),
             Float => ps!(WireTypeFixed32, 4, Value::F32, I::read_float),
             Double => ps!(WireTypeFixed64, 8, Value::F64, I::read_double),
-            Bytes => ss!(WireTypeLengthDelimited, Value::Bytes, I::read_bytes),
-            String => ss!(WireTypeLengthDelimited, Value::String, I::read_string),
-            Enum(_) => self.merge_enum(input, wire_type),
-            Message(ref m) => self.merge_message(input, descriptors, m, wire_type),
-            Group => unimplemented!(),
+            Bytes => self.merge_bytes(input, wire_type, max_alloc),
+            String => self.merge_string(input, wire_type, max_alloc),
+            Enum(_) => ps!(
+                WireTypeVarint,
+                Value::Enum,
+                |i: &mut I| i.read_raw_varint32().map(|v| v as i32)
+            ),
+            Message(ref m) => {
+                self.merge_message(input, descriptors, m, wire_type, limit, depth, max_alloc)
+            }
+            Group(ref m) => self.merge_group(
+                input,
+                descriptors,
+                m,
+                wire_type,
+                field.number(),
+                limit,
+                depth,
+                max_alloc,
+            ),
             UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
             UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
         }
     }
 
     #[inline]
-    fn size_with_tag(&self, tag: u32) -> u32 {
+    fn size_with_tag(
+        &self,
+        tag: u32,
+        field: &descriptor::FieldDescriptor,
+        descriptors: &descriptor::Descriptors,
+    ) -> u32 {
         match self {
-            Self::Singular(Some(Value::Bool(b))) => match b {
-                true => 2,
-                _ => 0,
-            },
-            Self::Singular(Some(Value::I32(x))) => match *x {
-                0 => 0,
-                _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
-            },
-            Self::Singular(Some(Value::I64(x))) => match *x {
-                0 => 0,
-                _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
-            },
-            Self::Singular(Some(Value::U32(x))) => match *x {
-                0 => 0,
-                _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
-            },
-            Self::Singular(Some(Value::U64(x))) => match *x {
-                0 => 0,
-                _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
-            },
-            Self::Singular(Some(Value::F32(_))) => 5,
-            Self::Singular(Some(Value::F64(_))) => 9,
-            Self::Singular(Some(Value::Bytes(v))) => protobuf::rt::bytes_size(tag, &v),
-            Self::Singular(Some(Value::String(s))) => protobuf::rt::string_size(tag, &s),
-            Self::Singular(Some(Value::Enum(x))) => match *x {
-                0 => 0,
-                _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
-            },
-            Self::Singular(Some(Value::Message(m))) => m.compute_size(),
+            Self::Singular(Some(v)) => value_size_with_tag(v, tag, field, descriptors),
             Self::Repeated(v) => {
-                let mut size = 0;
-                for x in v {
-                    // TODO: Avoid cloning here.
-                    size += Field::Singular(Some(x.clone())).size_with_tag(tag);
+                if field.is_packed() && !v.is_empty() {
+                    if let Some(kind) = packed_wire_kind(field.field_type(descriptors)) {
+                        let payload_len: u32 = v.iter().map(|x| packed_element_size(x, kind)).sum();
+                        return protobuf::rt::tag_size(tag) + varint_size(payload_len as u64) + payload_len;
+                    }
                 }
-                size
+                v.iter()
+                    .map(|x| value_size_with_tag(x, tag, field, descriptors))
+                    .sum()
             }
             Self::Singular(None) => 0,
         }
@@ -266,80 +645,27 @@ impl Field {
     pub fn write_to_with_tag(
         &self,
         tag: u32,
+        field: &descriptor::FieldDescriptor,
+        descriptors: &descriptor::Descriptors,
         os: &mut protobuf::CodedOutputStream,
         repeated_elem: bool,
     ) -> error::Result<()> {
         match self {
-            Self::Singular(Some(Value::Bool(b))) => {
-                if *b || repeated_elem {
-                    os.write_bool(tag, true)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::I32(x))) => {
-                if *x != 0 || repeated_elem {
-                    os.write_int32(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::I64(x))) => {
-                if *x != 0 || repeated_elem {
-                    os.write_int64(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::U32(x))) => {
-                if *x != 0 || repeated_elem {
-                    os.write_uint32(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::U64(x))) => {
-                if *x != 0 || repeated_elem {
-                    os.write_uint64(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::F32(x))) => {
-                if *x != 0 as f32 || repeated_elem {
-                    os.write_float(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::F64(x))) => {
-                if *x != 0 as f64 || repeated_elem {
-                    os.write_double(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::Bytes(v))) => {
-                if !v.is_empty() {
-                    os.write_bytes(tag, v.as_slice())?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::String(s))) => {
-                if !s.is_empty() || repeated_elem {
-                    os.write_string(tag, &s)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::Enum(x))) => {
-                if *x != 0 || repeated_elem {
-                    os.write_enum(tag, *x)?;
-                }
-                Ok(())
-            }
-            Self::Singular(Some(Value::Message(m))) => {
-                os.write_tag(tag, protobuf::wire_format::WireTypeLengthDelimited)?;
-                os.write_raw_varint32(m.size.get())?;
-                m.write_to(os)?;
-                Ok(())
-            }
+            Self::Singular(Some(v)) => write_value_with_tag(v, tag, field, descriptors, os, repeated_elem),
             Self::Repeated(v) => {
+                if field.is_packed() && !v.is_empty() {
+                    if let Some(kind) = packed_wire_kind(field.field_type(descriptors)) {
+                        os.write_tag(tag, protobuf::wire_format::WireTypeLengthDelimited)?;
+                        let payload_len: u32 = v.iter().map(|x| packed_element_size(x, kind)).sum();
+                        os.write_raw_varint32(payload_len)?;
+                        for x in v {
+                            write_packed_element(x, kind, os)?;
+                        }
+                        return Ok(());
+                    }
+                }
                 for x in v {
-                    // TODO: Avoid cloning here.
-                    Field::Singular(Some(x.clone())).write_to_with_tag(tag, os, true)?;
+                    write_value_with_tag(x, tag, field, descriptors, os, true)?;
                 }
                 Ok(())
             }
@@ -378,6 +704,7 @@ impl Field {
         expected_wire_type: wire_format::WireType,
         value_ctor: V,
         reader: R,
+        max_alloc: u64,
     ) -> error::Result<()>
     where
         V: Fn(A) -> Value,
@@ -385,6 +712,12 @@ impl Field {
     {
         if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
             let len = input.read_raw_varint64()?;
+            if len > max_alloc {
+                return Err(error::Error::MessageTooLarge {
+                    declared: len,
+                    limit: max_alloc,
+                });
+            }
 
             let old_limit = input.push_limit(len)?;
             while !input.eof()? {
@@ -404,21 +737,69 @@ impl Field {
         }
     }
 
+    /// Reads a `bytes` value, rejecting a declared length over `max_alloc`
+    /// before allocating a buffer for it.
     #[inline]
-    fn merge_enum(
+    fn merge_bytes(
         &mut self,
         input: &mut protobuf::CodedInputStream,
         actual_wire_type: wire_format::WireType,
+        max_alloc: u64,
     ) -> error::Result<()> {
-        if wire_format::WireType::WireTypeVarint == actual_wire_type {
-            let v = input.read_raw_varint32()? as i32;
-            self.put(Value::Enum(v));
-            Ok(())
-        } else {
-            Err(error::Error::BadWireType {
+        let bytes = self.read_bounded_bytes(input, actual_wire_type, max_alloc)?;
+        self.put(Value::Bytes(bytes));
+        Ok(())
+    }
+
+    /// Reads a `string` value, rejecting a declared length over `max_alloc`
+    /// before allocating a buffer for it.
+    #[inline]
+    fn merge_string(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        actual_wire_type: wire_format::WireType,
+        max_alloc: u64,
+    ) -> error::Result<()> {
+        let bytes = self.read_bounded_bytes(input, actual_wire_type, max_alloc)?;
+        let s = String::from_utf8(bytes).map_err(|_| error::Error::InvalidUtf8)?;
+        self.put(Value::String(s));
+        Ok(())
+    }
+
+    /// Reads a length-delimited run of raw bytes, rejecting a declared length
+    /// over `max_alloc` before allocating a buffer for it.
+    #[inline]
+    fn read_bounded_bytes(
+        &self,
+        input: &mut protobuf::CodedInputStream,
+        actual_wire_type: wire_format::WireType,
+        max_alloc: u64,
+    ) -> error::Result<Vec<u8>> {
+        if wire_format::WireType::WireTypeLengthDelimited != actual_wire_type {
+            return Err(error::Error::BadWireType {
                 wire_type: actual_wire_type,
-            })
+            });
+        }
+
+        let len = input.read_raw_varint64()?;
+        if len > max_alloc {
+            return Err(error::Error::MessageTooLarge {
+                declared: len,
+                limit: max_alloc,
+            });
         }
+        // `read_raw_bytes` below takes a `u32`; reject anything that would
+        // truncate on that cast (distinct from the `max_alloc` check above,
+        // which a caller may have configured above `u32::MAX`) before it can
+        // desync the rest of the parse by reading fewer bytes than declared.
+        if len > u32::MAX as u64 {
+            return Err(error::Error::MessageTooLarge {
+                declared: len,
+                limit: u32::MAX as u64,
+            });
+        }
+
+        Ok(input.read_raw_bytes(len as u32)?)
     }
 
     #[inline]
@@ -428,9 +809,23 @@ impl Field {
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
         actual_wire_type: wire_format::WireType,
+        limit: u32,
+        depth: u32,
+        max_alloc: u64,
     ) -> error::Result<()> {
         if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            if depth >= limit {
+                return Err(error::Error::RecursionLimitExceeded { limit });
+            }
+
             let len = input.read_raw_varint64()?;
+            if len > max_alloc {
+                return Err(error::Error::MessageTooLarge {
+                    declared: len,
+                    limit: max_alloc,
+                });
+            }
+
             let mut msg = match *self {
                 Field::Singular(ref mut o) => {
                     if let Some(Value::Message(m)) = o.take() {
@@ -443,7 +838,7 @@ impl Field {
             };
 
             let old_limit = input.push_limit(len)?;
-            msg.merge_from(descriptors, message, input)?;
+            msg.merge_from_bounded(descriptors, message, input, limit, depth + 1, max_alloc)?;
             input.pop_limit(old_limit);
 
             self.put(Value::Message(msg));
@@ -455,6 +850,83 @@ impl Field {
         }
     }
 
+    /// Merge a proto2 group from the given input stream into this field.
+    ///
+    /// Groups have no length prefix: instead of `push_limit`-ing a known
+    /// number of bytes like `merge_message` does, this reads tagged fields
+    /// until it sees the matching `WireTypeEndGroup` tag for `group_number`.
+    #[inline]
+    fn merge_group(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        actual_wire_type: wire_format::WireType,
+        group_number: i32,
+        limit: u32,
+        depth: u32,
+        max_alloc: u64,
+    ) -> error::Result<()> {
+        if wire_format::WireType::WireTypeStartGroup != actual_wire_type {
+            return Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+            });
+        }
+
+        if depth >= limit {
+            return Err(error::Error::RecursionLimitExceeded { limit });
+        }
+
+        let mut msg = match *self {
+            Field::Singular(ref mut o) => {
+                if let Some(Value::Message(m)) = o.take() {
+                    m
+                } else {
+                    Message::new(message)
+                }
+            }
+            _ => Message::new(message),
+        };
+
+        loop {
+            if input.eof()? {
+                return Err(error::Error::TruncatedGroup {
+                    field_number: group_number,
+                });
+            }
+
+            let (number, wire_type) = input.read_tag_unpack()?;
+            if wire_type == wire_format::WireType::WireTypeEndGroup {
+                if number as i32 != group_number {
+                    return Err(error::Error::BadGroupEnd {
+                        expected: group_number,
+                        found: number as i32,
+                    });
+                }
+                break;
+            }
+
+            if let Some(field) = message.field_by_number(number as i32) {
+                let value = msg.ensure_field(field);
+                value.merge_from(
+                    descriptors,
+                    field,
+                    input,
+                    wire_type,
+                    limit,
+                    depth + 1,
+                    max_alloc,
+                )?;
+            } else {
+                use protobuf::rt::read_unknown_or_skip_group as u;
+                u(number, wire_type, input, &mut msg.unknown)?;
+            }
+        }
+
+        self.put(Value::Message(msg));
+        Ok(())
+    }
+
     #[inline]
     fn put(&mut self, value: Value) {
         match *self {
@@ -463,3 +935,503 @@ impl Field {
         }
     }
 }
+
+/// Tagged wire size of a single `value`, as if writing one unpacked instance
+/// of it. Shared by `Field::size_with_tag`'s singular and (unpacked)
+/// repeated paths so repeated fields don't need to clone each element into a
+/// throwaway `Field::Singular` just to reuse this logic.
+fn value_size_with_tag(
+    value: &Value,
+    tag: u32,
+    field: &descriptor::FieldDescriptor,
+    descriptors: &descriptor::Descriptors,
+) -> u32 {
+    match value {
+        Value::Bool(b) => match b {
+            true => 2,
+            _ => 0,
+        },
+        Value::I32(x) => match *x {
+            0 => 0,
+            _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
+        },
+        Value::I64(x) => match *x {
+            0 => 0,
+            _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
+        },
+        Value::U32(x) => match *x {
+            0 => 0,
+            _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
+        },
+        Value::U64(x) => match *x {
+            0 => 0,
+            _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
+        },
+        Value::F32(_) => 5,
+        Value::F64(_) => 9,
+        Value::Bytes(v) => protobuf::rt::bytes_size(tag, v),
+        Value::String(s) => protobuf::rt::string_size(tag, s),
+        Value::Enum(x) => match *x {
+            0 => 0,
+            _ => protobuf::rt::value_size(tag, *x, wire_format::WireTypeVarint),
+        },
+        Value::Message(m) => match field.field_type(descriptors) {
+            descriptor::FieldType::Message(ref nested) => {
+                let len = m.compute_size(descriptors, nested);
+                protobuf::rt::tag_size(tag) + varint_size(len as u64) + len
+            }
+            // Groups have a start and an end tag instead of a length prefix.
+            descriptor::FieldType::Group(ref nested) => {
+                2 * protobuf::rt::tag_size(tag) + m.compute_size(descriptors, nested)
+            }
+            _ => unreachable!("Value::Message held by a non-message field"),
+        },
+    }
+}
+
+/// Writes a single `value` with `tag`, as either a singular field
+/// (`repeated_elem` false, where a default value is omitted) or one element
+/// of an unpacked repeated field (`repeated_elem` true, always written).
+/// Shared by `Field::write_to_with_tag`'s singular and (unpacked) repeated
+/// paths so repeated fields don't need to clone each element into a
+/// throwaway `Field::Singular` just to reuse this logic.
+fn write_value_with_tag(
+    value: &Value,
+    tag: u32,
+    field: &descriptor::FieldDescriptor,
+    descriptors: &descriptor::Descriptors,
+    os: &mut protobuf::CodedOutputStream,
+    repeated_elem: bool,
+) -> error::Result<()> {
+    match value {
+        Value::Bool(b) => {
+            if *b || repeated_elem {
+                os.write_bool(tag, *b)?;
+            }
+            Ok(())
+        }
+        Value::I32(x) => {
+            if *x != 0 || repeated_elem {
+                os.write_int32(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::I64(x) => {
+            if *x != 0 || repeated_elem {
+                os.write_int64(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::U32(x) => {
+            if *x != 0 || repeated_elem {
+                os.write_uint32(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::U64(x) => {
+            if *x != 0 || repeated_elem {
+                os.write_uint64(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::F32(x) => {
+            if *x != 0 as f32 || repeated_elem {
+                os.write_float(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::F64(x) => {
+            if *x != 0 as f64 || repeated_elem {
+                os.write_double(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::Bytes(v) => {
+            if !v.is_empty() || repeated_elem {
+                os.write_bytes(tag, v.as_slice())?;
+            }
+            Ok(())
+        }
+        Value::String(s) => {
+            if !s.is_empty() || repeated_elem {
+                os.write_string(tag, s)?;
+            }
+            Ok(())
+        }
+        Value::Enum(x) => {
+            if *x != 0 || repeated_elem {
+                os.write_enum(tag, *x)?;
+            }
+            Ok(())
+        }
+        Value::Message(m) => match field.field_type(descriptors) {
+            descriptor::FieldType::Message(ref nested) => {
+                os.write_tag(tag, protobuf::wire_format::WireTypeLengthDelimited)?;
+                os.write_raw_varint32(m.size.get())?;
+                m.write_to(descriptors, nested, os)?;
+                Ok(())
+            }
+            descriptor::FieldType::Group(ref nested) => {
+                os.write_tag(tag, protobuf::wire_format::WireTypeStartGroup)?;
+                m.write_to(descriptors, nested, os)?;
+                os.write_tag(tag, protobuf::wire_format::WireTypeEndGroup)?;
+                Ok(())
+            }
+            _ => unreachable!("Value::Message held by a non-message field"),
+        },
+    }
+}
+
+/// The wire representation used when packing repeated values of a field.
+#[derive(Clone, Copy)]
+enum PackedWireKind {
+    Varint,
+    Fixed32,
+    Fixed64,
+}
+
+/// The packed wire representation for `field_type`, or `None` if values of
+/// that type are never written in packed form (`Bytes`, `String`, `Message`,
+/// ...).
+fn packed_wire_kind(field_type: descriptor::FieldType) -> Option<PackedWireKind> {
+    use crate::descriptor::FieldType::*;
+    match field_type {
+        Bool | Int32 | Int64 | SInt32 | SInt64 | UInt32 | UInt64 | Enum(_) => {
+            Some(PackedWireKind::Varint)
+        }
+        Fixed32 | SFixed32 | Float => Some(PackedWireKind::Fixed32),
+        Fixed64 | SFixed64 | Double => Some(PackedWireKind::Fixed64),
+        _ => None,
+    }
+}
+
+/// The raw (tag-less) wire size of `value` when written as `kind`.
+fn packed_element_size(value: &Value, kind: PackedWireKind) -> u32 {
+    match kind {
+        PackedWireKind::Varint => varint_size(packed_varint_bits(value)),
+        PackedWireKind::Fixed32 => 4,
+        PackedWireKind::Fixed64 => 8,
+    }
+}
+
+/// Writes `value` as a tag-less element inside a packed payload.
+fn write_packed_element(
+    value: &Value,
+    kind: PackedWireKind,
+    os: &mut protobuf::CodedOutputStream,
+) -> error::Result<()> {
+    match kind {
+        PackedWireKind::Varint => os.write_raw_varint64(packed_varint_bits(value))?,
+        PackedWireKind::Fixed32 => os.write_raw_little_endian32(packed_fixed32_bits(value))?,
+        PackedWireKind::Fixed64 => os.write_raw_little_endian64(packed_fixed64_bits(value))?,
+    }
+    Ok(())
+}
+
+/// The 64-bit varint payload for a packed varint element, matching the
+/// encoding `write_to_with_tag` uses for the unpacked form of the same value.
+fn packed_varint_bits(value: &Value) -> u64 {
+    match value {
+        Value::Bool(b) => *b as u64,
+        Value::I32(x) => *x as i64 as u64,
+        Value::I64(x) => *x as u64,
+        Value::U32(x) => *x as u64,
+        Value::U64(x) => *x,
+        Value::Enum(x) => *x as i64 as u64,
+        _ => unreachable!("not a varint-packable value"),
+    }
+}
+
+fn packed_fixed32_bits(value: &Value) -> u32 {
+    match value {
+        Value::U32(x) => *x,
+        Value::I32(x) => *x as u32,
+        Value::F32(x) => x.to_bits(),
+        _ => unreachable!("not a 32-bit fixed-width value"),
+    }
+}
+
+fn packed_fixed64_bits(value: &Value) -> u64 {
+    match value {
+        Value::U64(x) => *x,
+        Value::I64(x) => *x as u64,
+        Value::F64(x) => x.to_bits(),
+        _ => unreachable!("not a 64-bit fixed-width value"),
+    }
+}
+
+/// Size in bytes of `v` encoded as a base-128 varint.
+fn varint_size(mut v: u64) -> u32 {
+    let mut size = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        size += 1;
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label, FieldDescriptorProto_Type,
+        FileDescriptorProto, FileDescriptorSet,
+    };
+
+    fn scalar_field(name: &str, number: i32, typ: FieldDescriptorProto_Type) -> FieldDescriptorProto {
+        let mut f = FieldDescriptorProto::new();
+        f.set_name(name.to_owned());
+        f.set_number(number);
+        f.set_field_type(typ);
+        f.set_label(FieldDescriptorProto_Label::LABEL_OPTIONAL);
+        f
+    }
+
+    fn message_field(
+        name: &str,
+        number: i32,
+        typ: FieldDescriptorProto_Type,
+        type_name: &str,
+    ) -> FieldDescriptorProto {
+        let mut f = scalar_field(name, number, typ);
+        f.set_type_name(type_name.to_owned());
+        f
+    }
+
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        let mut m = DescriptorProto::new();
+        m.set_name(name.to_owned());
+        for f in fields {
+            m.mut_field().push(f);
+        }
+        m
+    }
+
+    fn descriptors_for(messages: Vec<DescriptorProto>) -> descriptor::Descriptors {
+        let mut file = FileDescriptorProto::new();
+        file.set_name("test.proto".to_owned());
+        file.set_package("test".to_owned());
+        for m in messages {
+            file.mut_message_type().push(m);
+        }
+        let mut set = FileDescriptorSet::new();
+        set.mut_file().push(file);
+        descriptor::Descriptors::from_proto(&set)
+    }
+
+    fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                buf.push(byte);
+                break;
+            }
+            buf.push(byte | 0x80);
+        }
+    }
+
+    fn node_descriptor() -> DescriptorProto {
+        message(
+            "Node",
+            vec![message_field(
+                "child",
+                1,
+                FieldDescriptorProto_Type::TYPE_MESSAGE,
+                ".test.Node",
+            )],
+        )
+    }
+
+    fn nested_node_bytes(depth: u32) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for _ in 0..depth {
+            let mut next = vec![0x0A];
+            write_varint(&mut next, bytes.len() as u64);
+            next.extend_from_slice(&bytes);
+            bytes = next;
+        }
+        bytes
+    }
+
+    #[test]
+    fn recursion_limit_exceeded_at_default_depth() {
+        let descriptors = descriptors_for(vec![node_descriptor()]);
+        let message = descriptors.message_by_name("test.Node").unwrap();
+        let bytes = nested_node_bytes(DEFAULT_RECURSION_LIMIT + 1);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::RecursionLimitExceeded { limit }) => {
+                assert_eq!(limit, DEFAULT_RECURSION_LIMIT)
+            }
+            other => panic!("expected RecursionLimitExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recursion_limit_is_configurable() {
+        let descriptors = descriptors_for(vec![node_descriptor()]);
+        let message = descriptors.message_by_name("test.Node").unwrap();
+        let bytes = nested_node_bytes(3);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        m.set_recursion_limit(2);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::RecursionLimitExceeded { limit }) => assert_eq!(limit, 2),
+            other => panic!("expected RecursionLimitExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nesting_within_the_limit_succeeds() {
+        let descriptors = descriptors_for(vec![node_descriptor()]);
+        let message = descriptors.message_by_name("test.Node").unwrap();
+        let bytes = nested_node_bytes(3);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        m.merge_from(&descriptors, message, &mut input).unwrap();
+    }
+
+    fn group_descriptors() -> Vec<DescriptorProto> {
+        vec![
+            message(
+                "Parent",
+                vec![message_field(
+                    "body",
+                    2,
+                    FieldDescriptorProto_Type::TYPE_GROUP,
+                    ".test.Body",
+                )],
+            ),
+            message(
+                "Body",
+                vec![scalar_field("x", 1, FieldDescriptorProto_Type::TYPE_INT32)],
+            ),
+        ]
+    }
+
+    #[test]
+    fn group_round_trips() {
+        let descriptors = descriptors_for(group_descriptors());
+        let message = descriptors.message_by_name("test.Parent").unwrap();
+
+        // Start group 2, x = 5, end group 2.
+        let bytes = vec![0x13, 0x08, 0x05, 0x14];
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        m.merge_from(&descriptors, message, &mut input).unwrap();
+
+        match m.fields.get(&2) {
+            Some(Field::Singular(Some(Value::Message(body)))) => {
+                assert!(matches!(
+                    body.fields.get(&1),
+                    Some(Field::Singular(Some(Value::I32(5))))
+                ));
+            }
+            other => panic!("expected a group submessage, got {:?}", other),
+        }
+
+        let written = m.write_to_bytes(&descriptors, message).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn truncated_group_is_rejected() {
+        let descriptors = descriptors_for(group_descriptors());
+        let message = descriptors.message_by_name("test.Parent").unwrap();
+
+        // Start group 2, x = 5, no end tag.
+        let bytes = vec![0x13, 0x08, 0x05];
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::TruncatedGroup { field_number: 2 }) => {}
+            other => panic!("expected TruncatedGroup, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_group_end_is_rejected() {
+        let descriptors = descriptors_for(group_descriptors());
+        let message = descriptors.message_by_name("test.Parent").unwrap();
+
+        // Start group 2, x = 5, end group 3 (doesn't match the start).
+        let bytes = vec![0x13, 0x08, 0x05, 0x1C];
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::BadGroupEnd {
+                expected: 2,
+                found: 3,
+            }) => {}
+            other => panic!("expected BadGroupEnd, got {:?}", other),
+        }
+    }
+
+    fn bytes_holder_descriptor() -> DescriptorProto {
+        message(
+            "BytesHolder",
+            vec![scalar_field("data", 1, FieldDescriptorProto_Type::TYPE_BYTES)],
+        )
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        let descriptors = descriptors_for(vec![bytes_holder_descriptor()]);
+        let message = descriptors.message_by_name("test.BytesHolder").unwrap();
+
+        let declared = DEFAULT_READ_RAW_BYTES_MAX_ALLOC + 1;
+        let mut bytes = vec![0x0A];
+        write_varint(&mut bytes, declared);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::MessageTooLarge { declared: d, limit }) => {
+                assert_eq!(d, declared);
+                assert_eq!(limit, DEFAULT_READ_RAW_BYTES_MAX_ALLOC);
+            }
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn max_alloc_bytes_is_configurable() {
+        let descriptors = descriptors_for(vec![bytes_holder_descriptor()]);
+        let message = descriptors.message_by_name("test.BytesHolder").unwrap();
+
+        let mut bytes = vec![0x0A];
+        write_varint(&mut bytes, 3);
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        m.set_max_alloc_bytes(2);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::MessageTooLarge {
+                declared: 3,
+                limit: 2,
+            }) => {}
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn declared_length_past_u32_max_is_rejected_even_under_a_larger_cap() {
+        let descriptors = descriptors_for(vec![bytes_holder_descriptor()]);
+        let message = descriptors.message_by_name("test.BytesHolder").unwrap();
+
+        let declared = u32::MAX as u64 + 1;
+        let mut bytes = vec![0x0A];
+        write_varint(&mut bytes, declared);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = Message::new(message);
+        m.set_max_alloc_bytes(u64::MAX);
+        match m.merge_from(&descriptors, message, &mut input) {
+            Err(error::Error::MessageTooLarge { declared: d, limit }) => {
+                assert_eq!(d, declared);
+                assert_eq!(limit, u32::MAX as u64);
+            }
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+    }
+}